@@ -0,0 +1,263 @@
+/*
+ * Isabelle project
+ *
+ * Copyright 2023-2024 Maxim Menshikov
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining
+ * a copy of this software and associated documentation files (the “Software”),
+ * to deal in the Software without restriction, including without limitation
+ * the rights to use, copy, modify, merge, publish, distribute, sublicense,
+ * and/or sell copies of the Software, and to permit persons to whom the
+ * Software is furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included
+ * in all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS
+ * OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+ * FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+ * DEALINGS IN THE SOFTWARE.
+ */
+
+// In-memory per-key rate limiting: a token bucket for bursts plus a quota
+// per UTC day. State lives in the plugin actor and is lost on restart,
+// which only ever errs on the side of letting a request through.
+
+use isabelle_dm::data_model::item::Item;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Above this many tracked keys, idle entries are dropped on the next check.
+const PRUNE_THRESHOLD: usize = 4096;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct Limits {
+    /// Bucket capacity: requests allowed back to back.
+    pub(crate) burst: u64,
+    /// Time for one token to come back.
+    pub(crate) refill: Duration,
+    /// Requests per UTC day; 0 disables the quota.
+    pub(crate) per_day: u64,
+}
+
+impl Limits {
+    /// Read `<prefix>_burst`, `<prefix>_refill_secs` and `<prefix>_per_day`
+    /// from the settings item, falling back to `default` per field.
+    pub(crate) fn from_settings(settings: &Item, prefix: &str, default: Limits) -> Self {
+        Limits {
+            burst: settings
+                .safe_u64(&format!("{}_burst", prefix), default.burst)
+                .max(1),
+            refill: Duration::from_secs(
+                settings.safe_u64(&format!("{}_refill_secs", prefix), default.refill.as_secs()),
+            ),
+            per_day: settings.safe_u64(&format!("{}_per_day", prefix), default.per_day),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum Limited {
+    /// Too many requests in a short time.
+    Rate,
+    /// Daily quota used up.
+    Quota,
+}
+
+struct Entry {
+    tokens: f64,
+    last: SystemTime,
+    day: u64,
+    used_today: u64,
+}
+
+pub(crate) struct RateLimiter<K> {
+    entries: HashMap<K, Entry>,
+}
+
+impl<K> Default for RateLimiter<K> {
+    fn default() -> Self {
+        RateLimiter {
+            entries: HashMap::new(),
+        }
+    }
+}
+
+fn day_of(t: SystemTime) -> u64 {
+    t.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() / 86400
+}
+
+impl<K: Eq + Hash> RateLimiter<K> {
+    /// Charge one request to `key` if both the bucket and the daily quota
+    /// allow it. Rejected requests are not charged.
+    pub(crate) fn check(
+        &mut self,
+        key: K,
+        limits: &Limits,
+        now: SystemTime,
+    ) -> Result<(), Limited> {
+        if self.entries.len() > PRUNE_THRESHOLD {
+            self.prune(limits, now);
+        }
+        let day = day_of(now);
+        let e = self.entries.entry(key).or_insert(Entry {
+            tokens: limits.burst as f64,
+            last: now,
+            day,
+            used_today: 0,
+        });
+        verdict(e, limits, now)?;
+        e.tokens -= 1.0;
+        e.used_today += 1;
+        Ok(())
+    }
+
+    /// What `check` would say for `key`, without charging anything.
+    pub(crate) fn peek(
+        &mut self,
+        key: &K,
+        limits: &Limits,
+        now: SystemTime,
+    ) -> Result<(), Limited> {
+        match self.entries.get_mut(key) {
+            Some(e) => verdict(e, limits, now),
+            None => Ok(()),
+        }
+    }
+
+    /// Forget keys whose bucket is full again and whose quota day is over;
+    /// a fresh entry would behave the same.
+    fn prune(&mut self, limits: &Limits, now: SystemTime) {
+        let day = day_of(now);
+        self.entries.retain(|_, e| {
+            refill(e, limits, now);
+            e.tokens < limits.burst as f64 || e.day == day
+        });
+    }
+}
+
+/// Bring `e` up to `now` and say whether it has room for one more request.
+fn verdict(e: &mut Entry, limits: &Limits, now: SystemTime) -> Result<(), Limited> {
+    refill(e, limits, now);
+    let day = day_of(now);
+    if e.day != day {
+        e.day = day;
+        e.used_today = 0;
+    }
+    if limits.per_day > 0 && e.used_today >= limits.per_day {
+        return Err(Limited::Quota);
+    }
+    if e.tokens < 1.0 {
+        return Err(Limited::Rate);
+    }
+    Ok(())
+}
+
+fn refill(e: &mut Entry, limits: &Limits, now: SystemTime) {
+    // A clock step backwards just means no refill this time.
+    let elapsed = now.duration_since(e.last).unwrap_or_default();
+    let gained = if limits.refill.is_zero() {
+        f64::INFINITY
+    } else {
+        elapsed.as_secs_f64() / limits.refill.as_secs_f64()
+    };
+    e.tokens = (e.tokens + gained).min(limits.burst as f64);
+    e.last = e.last.max(now);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIMITS: Limits = Limits {
+        burst: 2,
+        refill: Duration::from_secs(60),
+        per_day: 3,
+    };
+
+    fn at(secs: u64) -> SystemTime {
+        // Start well inside a UTC day.
+        UNIX_EPOCH + Duration::from_secs(86400 * 1000 + 3600 + secs)
+    }
+
+    #[test]
+    fn bucket_allows_burst_then_refills() {
+        let limits = Limits {
+            per_day: 0,
+            ..LIMITS
+        };
+        let mut rl = RateLimiter::default();
+        assert_eq!(rl.check(1u64, &limits, at(0)), Ok(()));
+        assert_eq!(rl.check(1, &limits, at(0)), Ok(()));
+        assert_eq!(rl.check(1, &limits, at(1)), Err(Limited::Rate));
+        // Other keys have their own bucket.
+        assert_eq!(rl.check(2, &limits, at(1)), Ok(()));
+        assert_eq!(rl.check(1, &limits, at(61)), Ok(()));
+        assert_eq!(rl.check(1, &limits, at(62)), Err(Limited::Rate));
+        assert_eq!(rl.check(1, &limits, at(181)), Ok(()));
+        assert_eq!(rl.check(1, &limits, at(181)), Ok(()));
+        assert_eq!(rl.check(1, &limits, at(181)), Err(Limited::Rate));
+    }
+
+    #[test]
+    fn peek_does_not_charge() {
+        let mut rl = RateLimiter::default();
+        assert_eq!(rl.peek(&1u64, &LIMITS, at(0)), Ok(()));
+        assert_eq!(rl.check(1, &LIMITS, at(0)), Ok(()));
+        assert_eq!(rl.check(1, &LIMITS, at(0)), Ok(()));
+        assert_eq!(rl.peek(&1, &LIMITS, at(0)), Err(Limited::Rate));
+        assert_eq!(rl.peek(&1, &LIMITS, at(60)), Ok(()));
+        assert_eq!(rl.peek(&1, &LIMITS, at(60)), Ok(()));
+        assert_eq!(rl.check(1, &LIMITS, at(60)), Ok(()));
+        assert_eq!(rl.check(1, &LIMITS, at(60)), Err(Limited::Quota));
+    }
+
+    #[test]
+    fn quota_resets_on_next_day() {
+        let mut rl = RateLimiter::default();
+        for t in [0, 100, 200] {
+            assert_eq!(rl.check(1u64, &LIMITS, at(t)), Ok(()));
+        }
+        assert_eq!(rl.check(1, &LIMITS, at(1000)), Err(Limited::Quota));
+        assert_eq!(rl.check(1, &LIMITS, at(86400)), Ok(()));
+    }
+
+    #[test]
+    fn zero_quota_means_unlimited() {
+        let limits = Limits {
+            burst: 1,
+            refill: Duration::ZERO,
+            per_day: 0,
+        };
+        let mut rl = RateLimiter::default();
+        for _ in 0..100 {
+            assert_eq!(rl.check(1u64, &limits, at(0)), Ok(()));
+        }
+    }
+
+    #[test]
+    fn prune_drops_idle_entries_only() {
+        let mut rl = RateLimiter::default();
+        rl.check(1u64, &LIMITS, at(0)).unwrap();
+        rl.check(2u64, &LIMITS, at(86400)).unwrap();
+        rl.prune(&LIMITS, at(86400 + 1));
+        assert!(!rl.entries.contains_key(&1));
+        assert!(rl.entries.contains_key(&2));
+    }
+
+    #[test]
+    fn limits_from_settings() {
+        let mut settings = Item::new();
+        assert_eq!(Limits::from_settings(&settings, "x", LIMITS), LIMITS);
+        settings.set_u64("x_burst", 0);
+        settings.set_u64("x_per_day", 10);
+        let l = Limits::from_settings(&settings, "x", LIMITS);
+        assert_eq!(l.burst, 1);
+        assert_eq!(l.per_day, 10);
+        assert_eq!(l.refill, LIMITS.refill);
+    }
+}
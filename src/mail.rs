@@ -0,0 +1,329 @@
+/*
+ * Isabelle project
+ *
+ * Copyright 2023-2024 Maxim Menshikov
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining
+ * a copy of this software and associated documentation files (the “Software”),
+ * to deal in the Software without restriction, including without limitation
+ * the rights to use, copy, modify, merge, publish, distribute, sublicense,
+ * and/or sell copies of the Software, and to permit persons to whom the
+ * Software is furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included
+ * in all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS
+ * OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+ * FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+ * DEALINGS IN THE SOFTWARE.
+ */
+
+// Templates for the e-mails the plugin sends. Each message has a name
+// ("otp", ...) and is looked up per locale under the data path:
+//
+//   <data_path>/email-templates/<locale>/<name>.subject
+//   <data_path>/email-templates/<locale>/<name>.txt
+//   <data_path>/email-templates/<locale>/<name>.html   optional
+//
+// The locale comes from the `locale` field of the recipient's user item;
+// "de-AT" tries "de-at", then "de", then `security_email_default_locale`
+// (default "en"), then the built-in English text. All parts of a message
+// come from one locale, the first with a `.txt` body (or, lacking that,
+// with any part), so subject and body never mix languages; parts missing
+// there come from the built-in text. `{{var}}` is replaced by the value of
+// `var`, HTML-escaped in the HTML part; unknown variables render empty.
+//
+// Core's mail API carries a single body, so messages are never sent as
+// multipart text and HTML: the HTML part replaces the text one where
+// `security_email_html` says the deployment's mailer sends HTML.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const TEMPLATE_DIR: &str = "email-templates";
+const MAX_LOCALE_LEN: usize = 16;
+
+/// Built-in templates: name, subject, plain-text body.
+const BUILTIN: &[(&str, &str, &str)] = &[
+    (
+        "otp",
+        "Your login code",
+        "Hello {{name}},\n\nEnter this as password: {{code}}\n\n\
+         The code is valid for {{expiry}} minutes.\n\n{{site}}\n",
+    ),
+    (
+        "otp_link",
+        "Your login code",
+        "Hello {{name}},\n\nEnter this as password: {{code}}\n\n\
+         Or sign in by opening this link:\n{{link}}\n\n\
+         The code and the link are valid for {{expiry}} minutes and can be used once.\n\n\
+         {{site}}\n",
+    ),
+    (
+        "password_reset",
+        "Reset your password",
+        "Hello {{name}},\n\nSomeone asked to reset the password of your account. \
+         To choose a new one, open this link:\n{{link}}\n\n\
+         The link is valid for {{expiry}} minutes and can be used once. If this \
+         wasn't you, ignore this e-mail; your password stays as it is.\n\n{{site}}\n",
+    ),
+    (
+        "email_change",
+        "Confirm your new e-mail address",
+        "Hello {{name}},\n\nTo use {{email}} for your account, open this link:\n\
+         {{link}}\n\nThe link is valid for {{expiry}} minutes. Until then your old \
+         address stays in use.\n\n{{site}}\n",
+    ),
+    (
+        "email_changed",
+        "Your e-mail address was changed",
+        "Hello {{name}},\n\nThe e-mail address of your account was changed to \
+         {{email}}. If this wasn't you, open this link within {{expiry}} minutes to \
+         change it back:\n{{link}}\n\n{{site}}\n",
+    ),
+    (
+        "email_verify",
+        "Confirm your e-mail address",
+        "Hello {{name}},\n\nTo confirm {{email}} as the address of your account, open \
+         this link:\n{{link}}\n\nThe link is valid for {{expiry}} minutes.\n\n{{site}}\n",
+    ),
+    (
+        "account_approved",
+        "Your account was approved",
+        "Hello {{name}},\n\nYour account at {{site}} was approved. You can sign in \
+         here:\n{{link}}\n\n{{site}}\n",
+    ),
+    (
+        "invitation",
+        "You are invited to {{site}}",
+        "Hello,\n\nYou are invited to create an account at {{site}}. To sign up, open \
+         this link:\n{{link}}\n\nThe invitation is valid for {{expiry}} minutes.\n\n\
+         {{site}}\n",
+    ),
+    (
+        "login_reminder",
+        "Your login",
+        "Hello {{name}},\n\nYour login is: {{login}}\n\n\
+         If you didn't ask for this reminder, you can ignore this e-mail.\n\n{{site}}\n",
+    ),
+];
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Email {
+    pub(crate) subject: String,
+    pub(crate) text: String,
+    pub(crate) html: Option<String>,
+}
+
+pub(crate) struct Templates {
+    dir: PathBuf,
+    default_locale: String,
+}
+
+/// Lowercased `locale` if it is safe to use as a directory name.
+fn clean_locale(locale: &str) -> Option<String> {
+    let locale = locale.trim().to_ascii_lowercase().replace('_', "-");
+    let ok = !locale.is_empty()
+        && locale.len() <= MAX_LOCALE_LEN
+        && !locale.starts_with('-')
+        && locale
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-');
+    ok.then_some(locale)
+}
+
+fn escape_html(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+/// Replace every `{{var}}` in `template`. Values are inserted as-is after
+/// `escape`, so a value containing braces is never expanded again.
+fn substitute(template: &str, vars: &HashMap<&str, &str>, escape: fn(&str) -> String) -> String {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        match after.find("}}") {
+            Some(end) => {
+                let value = vars.get(after[..end].trim()).copied().unwrap_or("");
+                out.push_str(&escape(value));
+                rest = &after[end + 2..];
+            }
+            None => {
+                out.push_str(&rest[start..]);
+                rest = "";
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+impl Templates {
+    pub(crate) fn new(data_path: &str, default_locale: &str) -> Self {
+        Templates {
+            dir: Path::new(data_path).join(TEMPLATE_DIR),
+            default_locale: clean_locale(default_locale).unwrap_or_else(|| "en".to_string()),
+        }
+    }
+
+    /// Locale directories to try for `locale`, most specific first.
+    fn candidates(&self, locale: &str) -> Vec<String> {
+        let mut out = Vec::new();
+        if let Some(l) = clean_locale(locale) {
+            if let Some((lang, _)) = l.split_once('-') {
+                out.push(l.clone());
+                out.push(lang.to_string());
+            } else {
+                out.push(l);
+            }
+        }
+        out.push(self.default_locale.clone());
+        out.dedup();
+        out
+    }
+
+    fn part(&self, locale: &str, name: &str, ext: &str) -> Option<String> {
+        fs::read_to_string(self.dir.join(locale).join(format!("{}.{}", name, ext))).ok()
+    }
+
+    /// The one locale of `candidates` message `name` is rendered in.
+    fn pick<'a>(&self, candidates: &'a [String], name: &str) -> Option<&'a str> {
+        let has =
+            |l: &String, ext: &str| self.dir.join(l).join(format!("{}.{}", name, ext)).is_file();
+        candidates
+            .iter()
+            .find(|l| has(l, "txt"))
+            .or_else(|| {
+                candidates
+                    .iter()
+                    .find(|l| has(l, "subject") || has(l, "html"))
+            })
+            .map(String::as_str)
+    }
+
+    /// Message `name` for a recipient preferring `locale`.
+    pub(crate) fn render(&self, name: &str, locale: &str, vars: &[(&str, &str)]) -> Email {
+        let vars: HashMap<&str, &str> = vars.iter().copied().collect();
+        let candidates = self.candidates(locale);
+        let locale = self.pick(&candidates, name);
+        let part = |ext| locale.and_then(|l| self.part(l, name, ext));
+        let builtin = BUILTIN.iter().find(|b| b.0 == name);
+        let subject = part("subject")
+            .or_else(|| builtin.map(|b| b.1.to_string()))
+            .unwrap_or_else(|| name.to_string());
+        let text = part("txt")
+            .or_else(|| builtin.map(|b| b.2.to_string()))
+            .unwrap_or_default();
+        let html = part("html");
+        Email {
+            // Header injection: a template or value must not add headers.
+            subject: substitute(subject.trim(), &vars, str::to_string).replace(['\r', '\n'], " "),
+            text: substitute(&text, &vars, str::to_string),
+            html: html.map(|h| substitute(&h, &vars, escape_html)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(dir: &Path, locale: &str, file: &str, body: &str) {
+        let d = dir.join(TEMPLATE_DIR).join(locale);
+        fs::create_dir_all(&d).unwrap();
+        fs::write(d.join(file), body).unwrap();
+    }
+
+    #[test]
+    fn builtin_used_without_files() {
+        let t = Templates::new("/nonexistent", "en");
+        let m = t.render("otp", "de", &[("code", "123456"), ("expiry", "15")]);
+        assert_eq!(m.subject, "Your login code");
+        assert!(m.text.contains("Enter this as password: 123456"));
+        assert!(m.text.contains("valid for 15 minutes"));
+        assert!(!m.text.contains("{{"));
+        assert_eq!(m.html, None);
+    }
+
+    #[test]
+    fn locale_falls_back_to_language_then_default() {
+        let dir = tempfile::tempdir().unwrap();
+        let dp = dir.path().to_str().unwrap();
+        write(dir.path(), "de", "otp.subject", "Ihr Code für {{site}}\n");
+        write(dir.path(), "de", "otp.txt", "Hallo {{name}}: {{code}}");
+        write(dir.path(), "de-at", "otp.subject", "Dei Code\n");
+        write(dir.path(), "de-ch", "otp.subject", "Ihr Code\n");
+        write(dir.path(), "de-ch", "otp.txt", "Grüezi {{name}}: {{code}}");
+        write(dir.path(), "fr", "otp.subject", "Votre code\n");
+        write(dir.path(), "fr", "otp.txt", "Bonjour {{name}}: {{code}}");
+        let t = Templates::new(dp, "fr");
+        let vars = [("name", "Alice"), ("code", "1"), ("site", "Isabelle")];
+
+        let m = t.render("otp", "de-CH", &vars);
+        assert_eq!(m.subject, "Ihr Code");
+        assert_eq!(m.text, "Grüezi Alice: 1");
+        // "de-at" has no body, so the whole message is "de".
+        let m = t.render("otp", "de_AT", &vars);
+        assert_eq!(m.subject, "Ihr Code für Isabelle");
+        assert_eq!(m.text, "Hallo Alice: 1");
+        // Unknown or unusable locales get the default.
+        for l in ["", "xx", "../de", "de/../../etc"] {
+            let m = t.render("otp", l, &vars);
+            assert_eq!(m.subject, "Votre code");
+            assert_eq!(m.text, "Bonjour Alice: 1");
+        }
+    }
+
+    #[test]
+    fn html_part_is_escaped() {
+        let dir = tempfile::tempdir().unwrap();
+        write(
+            dir.path(),
+            "en",
+            "otp.html",
+            "<p>Hi {{ name }}, {{code}}{{nope}}</p>",
+        );
+        let t = Templates::new(dir.path().to_str().unwrap(), "en");
+        let m = t.render("otp", "en", &[("name", "<b>&</b>"), ("code", "{{name}}")]);
+        assert_eq!(
+            m.html.unwrap(),
+            "<p>Hi &lt;b&gt;&amp;&lt;/b&gt;, {{name}}</p>"
+        );
+        assert!(m.text.contains("Hello <b>&</b>"));
+    }
+
+    #[test]
+    fn subject_stays_on_one_line() {
+        let t = Templates::new("/nonexistent", "en");
+        let m = t.render("missing", "en", &[]);
+        assert_eq!(m.subject, "missing");
+        assert_eq!(m.text, "");
+        assert_eq!(
+            substitute("a {{x", &HashMap::new(), str::to_string),
+            "a {{x"
+        );
+        let dir = tempfile::tempdir().unwrap();
+        write(dir.path(), "en", "otp.subject", "Code {{code}}");
+        let t = Templates::new(dir.path().to_str().unwrap(), "en");
+        let m = t.render("otp", "en", &[("code", "1\r\nBcc: x@e.com")]);
+        assert_eq!(m.subject, "Code 1  Bcc: x@e.com");
+    }
+}
@@ -0,0 +1,349 @@
+/*
+ * Isabelle project
+ *
+ * Copyright 2023-2024 Maxim Menshikov
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining
+ * a copy of this software and associated documentation files (the “Software”),
+ * to deal in the Software without restriction, including without limitation
+ * the rights to use, copy, modify, merge, publish, distribute, sublicense,
+ * and/or sell copies of the Software, and to permit persons to whom the
+ * Software is furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included
+ * in all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS
+ * OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+ * FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+ * DEALINGS IN THE SOFTWARE.
+ */
+
+// Persistent queue for outgoing mail under `<data_path>/outbox/`. Every
+// message is written to its own file before it is handed to core, so a
+// crash or restart in between doesn't lose it; the `security_outbox_drain`
+// periodic job delivers whatever is left. Core's mail API reports no
+// failures, so a message leaves the queue once handed over; there is
+// nothing to retry.
+//
+// Messages carry login codes and links, so none is kept longer than it is
+// of use: one still queued when its lifetime runs out is deleted unsent
+// and only logged. Settings:
+//
+//   security_outbox_max_age_secs   lifetime of a queued message; default 900
+//   security_outbox_dedupe_secs    after a message of a group went out,
+//                                  others of the group within this window
+//                                  aren't sent; default 120
+
+use crate::mail::Email;
+use isabelle_dm::data_model::item::Item;
+use log::info;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+const OUTBOX_DIR: &str = "outbox";
+
+pub(crate) struct Policy {
+    max_age_secs: u64,
+    dedupe_secs: u64,
+}
+
+impl Policy {
+    pub(crate) fn from_settings(settings: &Item) -> Self {
+        Policy {
+            max_age_secs: settings.safe_u64("security_outbox_max_age_secs", 900),
+            dedupe_secs: settings.safe_u64("security_outbox_dedupe_secs", 120),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) struct Entry {
+    pub(crate) to: String,
+    pub(crate) email: Email,
+    /// Messages of one group stand in for each other, e.g. login codes for
+    /// the same address: a newer one replaces an unsent older one, and none
+    /// goes out shortly after another did. Empty for messages that must
+    /// all be sent.
+    group: String,
+    /// Unix time the message was queued.
+    queued: u64,
+}
+
+impl Entry {
+    pub(crate) fn new(to: &str, email: Email, group: &str, now: u64) -> Self {
+        Entry {
+            to: to.to_string(),
+            email,
+            group: group.to_string(),
+            queued: now,
+        }
+    }
+
+    fn expired(&self, policy: &Policy, now: u64) -> bool {
+        now >= self.queued.saturating_add(policy.max_age_secs)
+    }
+
+    fn encode(&self) -> String {
+        let mut fields = vec![
+            ("to", self.to.clone()),
+            ("subject", self.email.subject.clone()),
+            ("text", self.email.text.clone()),
+            ("group", self.group.clone()),
+            ("queued", self.queued.to_string()),
+        ];
+        if let Some(html) = &self.email.html {
+            fields.push(("html", html.clone()));
+        }
+        serde_urlencoded::to_string(fields).unwrap_or_default()
+    }
+
+    fn decode(s: &str) -> Option<Self> {
+        let mut f: HashMap<String, String> = serde_urlencoded::from_str(s).ok()?;
+        let mut take = |k: &str| f.remove(k);
+        Some(Entry {
+            to: take("to")?,
+            email: Email {
+                subject: take("subject")?,
+                text: take("text")?,
+                html: take("html"),
+            },
+            group: take("group").unwrap_or_default(),
+            queued: take("queued")?.parse().ok()?,
+        })
+    }
+}
+
+pub(crate) struct Outbox {
+    dir: PathBuf,
+}
+
+/// Write `data` to `path` through a temporary file, so a reader never sees
+/// half a message.
+fn write_atomic(path: &Path, data: &str) -> io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, data)?;
+    fs::rename(&tmp, path)
+}
+
+impl Outbox {
+    pub(crate) fn new(data_path: &str) -> Self {
+        Outbox {
+            dir: Path::new(data_path).join(OUTBOX_DIR),
+        }
+    }
+
+    fn pending(&self) -> Vec<(PathBuf, Option<Entry>)> {
+        let mut out: Vec<(PathBuf, Option<Entry>)> = fs::read_dir(&self.dir)
+            .into_iter()
+            .flatten()
+            .flatten()
+            .map(|e| e.path())
+            .filter(|p| p.extension().is_some_and(|e| e == "msg"))
+            .map(|p| {
+                let entry = fs::read_to_string(&p).ok().and_then(|s| Entry::decode(&s));
+                (p, entry)
+            })
+            .collect();
+        // Names start with the creation time: oldest first.
+        out.sort_by(|a, b| a.0.cmp(&b.0));
+        out
+    }
+
+    /// Marker holding the time a message of `group` last went out. Group
+    /// names contain addresses, so the file is named after their hash.
+    fn sent_marker(&self, group: &str) -> PathBuf {
+        let name: String = Sha256::digest(group.as_bytes())
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        self.dir.join("sent").join(name)
+    }
+
+    /// Queue `entry`. `Ok(false)` if a message of its group went out within
+    /// the de-duplication window.
+    pub(crate) fn enqueue(&self, entry: &Entry, policy: &Policy, now: u64) -> io::Result<bool> {
+        fs::create_dir_all(&self.dir)?;
+        if !entry.group.is_empty() {
+            let sent_at = fs::read_to_string(self.sent_marker(&entry.group))
+                .ok()
+                .and_then(|s| s.trim().parse::<u64>().ok());
+            if sent_at.is_some_and(|t| now < t.saturating_add(policy.dedupe_secs)) {
+                return Ok(false);
+            }
+            for (path, queued) in self.pending() {
+                if queued.is_some_and(|q| q.group == entry.group) {
+                    fs::remove_file(&path)?;
+                }
+            }
+        }
+        let mut rnd = [0u8; 4];
+        crate::secrets::random_bytes(&mut rnd)?;
+        let name = format!(
+            "{:012}-{}.msg",
+            now,
+            rnd.iter().map(|b| format!("{:02x}", b)).collect::<String>()
+        );
+        write_atomic(&self.dir.join(name), &entry.encode())?;
+        Ok(true)
+    }
+
+    /// Messages to hand over, oldest first. Expired ones are deleted; so are
+    /// files that can't be parsed, as there is no telling which part of
+    /// them is secret.
+    pub(crate) fn due(&self, policy: &Policy, now: u64) -> Vec<(PathBuf, Entry)> {
+        let mut out = Vec::new();
+        for (path, entry) in self.pending() {
+            match entry {
+                Some(e) if e.expired(policy, now) => {
+                    info!("Dropped e-mail to {} queued at {}: expired", e.to, e.queued);
+                    let _ = fs::remove_file(&path);
+                }
+                Some(e) => out.push((path, e)),
+                None => {
+                    info!("Removed unreadable outbox entry {}", path.display());
+                    let _ = fs::remove_file(&path);
+                }
+            }
+        }
+        out
+    }
+
+    pub(crate) fn delivered(&self, path: &Path, entry: &Entry, now: u64) -> io::Result<()> {
+        if !entry.group.is_empty() {
+            fs::create_dir_all(self.dir.join("sent"))?;
+            fs::write(self.sent_marker(&entry.group), now.to_string())?;
+        }
+        fs::remove_file(path)
+    }
+
+    /// Drop de-duplication markers older than the window.
+    pub(crate) fn prune_sent(&self, policy: &Policy, now: u64) {
+        for e in fs::read_dir(self.dir.join("sent"))
+            .into_iter()
+            .flatten()
+            .flatten()
+        {
+            let sent_at = fs::read_to_string(e.path())
+                .ok()
+                .and_then(|s| s.trim().parse::<u64>().ok())
+                .unwrap_or(0);
+            if now >= sent_at.saturating_add(policy.dedupe_secs) {
+                let _ = fs::remove_file(e.path());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: u64 = 1_700_000_000;
+
+    fn policy() -> Policy {
+        Policy::from_settings(&Item::new())
+    }
+
+    fn entry(to: &str, text: &str, group: &str) -> Entry {
+        let email = Email {
+            subject: "Code".to_string(),
+            text: text.to_string(),
+            html: Some("<p>&amp; =%</p>".to_string()),
+        };
+        Entry::new(to, email, group, NOW)
+    }
+
+    fn deliver_all(ob: &Outbox, now: u64) {
+        for (path, e) in ob.due(&policy(), now) {
+            ob.delivered(&path, &e, now).unwrap();
+        }
+    }
+
+    #[test]
+    fn entries_round_trip() {
+        let e = entry("a@e.com", "line 1\nline 2 & more=", "otp:a@e.com");
+        assert_eq!(Entry::decode(&e.encode()), Some(e));
+        assert_eq!(Entry::decode("to=a"), None);
+    }
+
+    #[test]
+    fn group_is_sent_once_per_window() {
+        let dir = tempfile::tempdir().unwrap();
+        let ob = Outbox::new(dir.path().to_str().unwrap());
+        let p = policy();
+        assert!(ob
+            .enqueue(&entry("a@e.com", "111111", "otp:a@e.com"), &p, NOW)
+            .unwrap());
+        deliver_all(&ob, NOW);
+
+        // A fresh code for the same group is held back...
+        let next = entry("a@e.com", "222222", "otp:a@e.com");
+        assert!(!ob.enqueue(&next, &p, NOW + 60).unwrap());
+        // ...unlike other groups and ungrouped messages, even identical ones.
+        assert!(ob
+            .enqueue(&entry("b@e.com", "111111", "otp:b@e.com"), &p, NOW)
+            .unwrap());
+        assert!(ob.enqueue(&entry("a@e.com", "x", ""), &p, NOW).unwrap());
+        assert!(ob.enqueue(&entry("a@e.com", "x", ""), &p, NOW).unwrap());
+        assert_eq!(ob.due(&p, NOW).len(), 3);
+
+        ob.prune_sent(&p, NOW + p.dedupe_secs);
+        assert!(ob.enqueue(&next, &p, NOW + p.dedupe_secs).unwrap());
+    }
+
+    #[test]
+    fn newer_message_of_group_replaces_unsent_one() {
+        let dir = tempfile::tempdir().unwrap();
+        let ob = Outbox::new(dir.path().to_str().unwrap());
+        let p = policy();
+        ob.enqueue(&entry("a@e.com", "111111", "otp:a@e.com"), &p, NOW)
+            .unwrap();
+        ob.enqueue(&entry("b@e.com", "222222", "otp:b@e.com"), &p, NOW)
+            .unwrap();
+        ob.enqueue(&entry("a@e.com", "333333", "otp:a@e.com"), &p, NOW)
+            .unwrap();
+        let texts: Vec<String> = ob
+            .due(&p, NOW)
+            .into_iter()
+            .map(|(_, e)| e.email.text)
+            .collect();
+        assert_eq!(texts.len(), 2);
+        assert!(texts.contains(&"222222".to_string()));
+        assert!(texts.contains(&"333333".to_string()));
+    }
+
+    #[test]
+    fn expired_messages_leave_nothing_behind() {
+        let dir = tempfile::tempdir().unwrap();
+        let ob = Outbox::new(dir.path().to_str().unwrap());
+        let p = policy();
+        ob.enqueue(&entry("a@e.com", "123456", "otp:a@e.com"), &p, NOW)
+            .unwrap();
+        assert_eq!(ob.due(&p, NOW + p.max_age_secs - 1).len(), 1);
+        assert!(ob.due(&p, NOW + p.max_age_secs).is_empty());
+        let left: Vec<_> = fs::read_dir(dir.path().join("outbox"))
+            .unwrap()
+            .flatten()
+            .collect();
+        assert!(left.is_empty());
+    }
+
+    #[test]
+    fn unreadable_files_do_not_block_the_queue() {
+        let dir = tempfile::tempdir().unwrap();
+        let ob = Outbox::new(dir.path().to_str().unwrap());
+        ob.enqueue(&entry("a@e.com", "x", ""), &policy(), NOW)
+            .unwrap();
+        let junk = dir.path().join("outbox/000000000000-junk.msg");
+        fs::write(&junk, "%%%").unwrap();
+        assert_eq!(ob.due(&policy(), NOW).len(), 1);
+        assert!(!junk.exists());
+    }
+}
@@ -0,0 +1,338 @@
+/*
+ * Isabelle project
+ *
+ * Copyright 2023-2024 Maxim Menshikov
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining
+ * a copy of this software and associated documentation files (the “Software”),
+ * to deal in the Software without restriction, including without limitation
+ * the rights to use, copy, modify, merge, publish, distribute, sublicense,
+ * and/or sell copies of the Software, and to permit persons to whom the
+ * Software is furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included
+ * in all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS
+ * OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+ * FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+ * DEALINGS IN THE SOFTWARE.
+ */
+
+// Hashcash-style proof of work for unprotected routes, so deployments
+// without access to hosted CAPTCHAs can still make bulk requests costly.
+// A challenge is `<expires>.<bits>.<nonce>.<sig>`, signed so neither the
+// expiry nor the difficulty can be changed; a solution is a decimal
+// counter such that SHA-256 of `<challenge>:<counter>` starts with `bits`
+// zero bits. Settings:
+//
+//   security_pow                   require solutions; default false
+//   security_pow_base_bits         difficulty at a normal rate; default 16
+//   security_pow_max_bits          cap on the difficulty; default 24
+//   security_pow_window_secs       window the rate is measured over; 60
+//   security_pow_rate_threshold    solved challenges per window before
+//                                  the difficulty rises; default 30
+//
+// Each doubling of the rate of solved challenges over the threshold adds a
+// bit, i.e. doubles the expected work. Only solutions count: fetching a
+// challenge is free, so counting those would let anyone drive the
+// difficulty up for everybody else without doing any work. Challenges more
+// than a bit easier than the current difficulty are refused, so easy ones
+// can't be stockpiled while the rate is low and spent once it has risen.
+// State lives in the plugin actor.
+//
+// Known gap: login codes are requested from core, which only tells the
+// plugin afterwards through the `Otp` hook and takes no answer, so that
+// route can't be made to require a solution from here.
+
+use crate::secrets::{self, SecretKey};
+use isabelle_dm::data_model::item::Item;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const CONTEXT: &str = "pow";
+/// A challenge must be solved and used within this time.
+const CHALLENGE_TTL: Duration = Duration::from_secs(5 * 60);
+/// Bits a challenge may fall short of the current difficulty, for ones
+/// issued just before it rose.
+const SLACK_BITS: u32 = 1;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct Policy {
+    pub(crate) base_bits: u32,
+    pub(crate) max_bits: u32,
+    pub(crate) window: Duration,
+    pub(crate) threshold: u64,
+}
+
+impl Policy {
+    pub(crate) fn from_settings(settings: &Item) -> Self {
+        let base_bits = settings.safe_u64("security_pow_base_bits", 16).min(64) as u32;
+        Policy {
+            base_bits,
+            max_bits: (settings.safe_u64("security_pow_max_bits", 24).min(64) as u32)
+                .max(base_bits),
+            window: Duration::from_secs(settings.safe_u64("security_pow_window_secs", 60).max(1)),
+            threshold: settings.safe_u64("security_pow_rate_threshold", 30).max(1),
+        }
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct Pow {
+    /// When recent challenges were solved, oldest first.
+    recent: VecDeque<SystemTime>,
+    /// Challenges already used, with their expiry, against replays.
+    spent: HashMap<String, u64>,
+}
+
+fn unix(t: SystemTime) -> u64 {
+    t.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+/// Number of leading zero bits of `hash`.
+fn leading_zero_bits(hash: &[u8]) -> u32 {
+    let mut bits = 0;
+    for b in hash {
+        bits += b.leading_zeros();
+        if *b != 0 {
+            break;
+        }
+    }
+    bits
+}
+
+fn work(challenge: &str, solution: &str) -> u32 {
+    leading_zero_bits(&Sha256::digest(format!("{}:{}", challenge, solution)))
+}
+
+impl Pow {
+    /// Forget solutions that fell out of the window at `now`.
+    fn expire(&mut self, policy: &Policy, now: SystemTime) {
+        while let Some(t) = self.recent.front() {
+            match now.duration_since(*t) {
+                Ok(age) if age >= policy.window => {
+                    self.recent.pop_front();
+                }
+                _ => break,
+            }
+        }
+    }
+
+    /// Difficulty for a challenge issued at `now`.
+    fn difficulty(&mut self, policy: &Policy, now: SystemTime) -> u32 {
+        self.expire(policy, now);
+        let n = self.recent.len() as u64;
+        let extra = (n / policy.threshold + 1).ilog2();
+        (policy.base_bits + extra).min(policy.max_bits)
+    }
+
+    /// A new challenge and its difficulty in bits.
+    pub(crate) fn issue(
+        &mut self,
+        key: &SecretKey,
+        policy: &Policy,
+        now: SystemTime,
+    ) -> io::Result<(String, u32)> {
+        let bits = self.difficulty(policy, now);
+        let mut nonce = [0u8; 16];
+        secrets::random_bytes(&mut nonce)?;
+        let payload = format!(
+            "{}.{}.{}",
+            unix(now) + CHALLENGE_TTL.as_secs(),
+            bits,
+            secrets::to_hex(&nonce)
+        );
+        let sig = key.sign(CONTEXT, &payload);
+        Ok((format!("{}.{}", payload, sig), bits))
+    }
+
+    /// Accept `solution` to `challenge` once, counting it towards the rate.
+    pub(crate) fn verify(
+        &mut self,
+        key: &SecretKey,
+        policy: &Policy,
+        challenge: &str,
+        solution: &str,
+        at: SystemTime,
+    ) -> Result<(), &'static str> {
+        let now = unix(at);
+        self.spent.retain(|_, expires| *expires > now);
+
+        let parts: Vec<&str> = challenge.split('.').collect();
+        let [expires, bits, nonce, sig] = parts[..] else {
+            return Err("malformed challenge");
+        };
+        if !key.verify(CONTEXT, &format!("{}.{}.{}", expires, bits, nonce), sig) {
+            return Err("forged challenge");
+        }
+        let (Ok(expires), Ok(bits)) = (expires.parse::<u64>(), bits.parse::<u32>()) else {
+            return Err("malformed challenge");
+        };
+        if now >= expires {
+            return Err("expired challenge");
+        }
+        if self.spent.contains_key(challenge) {
+            return Err("challenge already used");
+        }
+        if bits.saturating_add(SLACK_BITS) < self.difficulty(policy, at) {
+            return Err("challenge too easy");
+        }
+        if solution.is_empty()
+            || !solution.bytes().all(|b| b.is_ascii_digit())
+            || work(challenge, solution) < bits
+        {
+            return Err("wrong solution");
+        }
+        self.spent.insert(challenge.to_string(), expires);
+        self.expire(policy, at);
+        self.recent.push_back(at);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    fn test_key() -> SecretKey {
+        let mut settings = Item::new();
+        settings.set_str("security_secret_key", &"ab".repeat(32));
+        SecretKey::load(&settings, "").unwrap()
+    }
+
+    /// What a client does: count up until the hash has enough zero bits.
+    pub(crate) fn solve(challenge: &str) -> String {
+        let bits: u32 = challenge.split('.').nth(1).unwrap().parse().unwrap();
+        (0u64..)
+            .map(|n| n.to_string())
+            .find(|s| work(challenge, s) >= bits)
+            .unwrap()
+    }
+
+    fn policy(base_bits: u32) -> Policy {
+        Policy {
+            base_bits,
+            max_bits: base_bits + 2,
+            window: Duration::from_secs(60),
+            threshold: 2,
+        }
+    }
+
+    fn at(secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(1_700_000_000 + secs)
+    }
+
+    #[test]
+    fn counts_leading_zero_bits() {
+        assert_eq!(leading_zero_bits(&[0xff]), 0);
+        assert_eq!(leading_zero_bits(&[0x00, 0x10]), 11);
+        assert_eq!(leading_zero_bits(&[0x00, 0x00]), 16);
+    }
+
+    #[test]
+    fn difficulty_rises_with_rate_of_solutions() {
+        let key = test_key();
+        let mut pow = Pow::default();
+        let p = policy(1);
+        // Fetching challenges costs nothing and moves nothing.
+        for _ in 0..20 {
+            assert_eq!(pow.issue(&key, &p, at(0)).unwrap().1, 1);
+        }
+        let mut bits = Vec::new();
+        for _ in 0..8 {
+            let (challenge, b) = pow.issue(&key, &p, at(0)).unwrap();
+            bits.push(b);
+            let solution = solve(&challenge);
+            pow.verify(&key, &p, &challenge, &solution, at(0)).unwrap();
+        }
+        assert_eq!(bits, [1, 1, 2, 2, 2, 2, 3, 3]);
+        // Back to normal once the window has passed.
+        assert_eq!(pow.difficulty(&p, at(60)), 1);
+    }
+
+    #[test]
+    fn stockpiled_easy_challenges_refused_once_rate_rises() {
+        let key = test_key();
+        let mut pow = Pow::default();
+        let p = policy(1);
+        let (stocked, bits) = pow.issue(&key, &p, at(0)).unwrap();
+        assert_eq!(bits, 1);
+        let stocked_solution = solve(&stocked);
+        let mut recent = None;
+        while pow.difficulty(&p, at(1)) < 3 {
+            let (challenge, bits) = pow.issue(&key, &p, at(1)).unwrap();
+            if bits == 2 && recent.is_none() {
+                recent = Some(challenge);
+                continue;
+            }
+            let solution = solve(&challenge);
+            pow.verify(&key, &p, &challenge, &solution, at(1)).unwrap();
+        }
+        assert_eq!(
+            pow.verify(&key, &p, &stocked, &stocked_solution, at(2)),
+            Err("challenge too easy")
+        );
+        // Within the slack, a challenge issued just before the rise is fine.
+        let recent = recent.unwrap();
+        let solution = solve(&recent);
+        assert_eq!(pow.verify(&key, &p, &recent, &solution, at(2)), Ok(()));
+    }
+
+    #[test]
+    fn settings_bound_difficulty() {
+        let mut settings = Item::new();
+        settings.set_u64("security_pow_base_bits", 20);
+        settings.set_u64("security_pow_max_bits", 12);
+        let p = Policy::from_settings(&settings);
+        assert_eq!((p.base_bits, p.max_bits), (20, 20));
+        assert_eq!(Policy::from_settings(&Item::new()).base_bits, 16);
+    }
+
+    #[test]
+    fn solution_accepted_once() {
+        let key = test_key();
+        let mut pow = Pow::default();
+        let (challenge, bits) = pow.issue(&key, &policy(8), at(0)).unwrap();
+        assert_eq!(bits, 8);
+        let solution = solve(&challenge);
+        assert!(pow.verify(&key, &policy(8), &challenge, "", at(1)).is_err());
+        assert_eq!(
+            pow.verify(&key, &policy(8), &challenge, &solution, at(1)),
+            Ok(())
+        );
+        assert_eq!(
+            pow.verify(&key, &policy(8), &challenge, &solution, at(2)),
+            Err("challenge already used")
+        );
+    }
+
+    #[test]
+    fn tampered_or_expired_challenges_rejected() {
+        let key = test_key();
+        let mut pow = Pow::default();
+        let (challenge, _) = pow.issue(&key, &policy(8), at(0)).unwrap();
+        let easier = challenge.replacen(".8.", ".0.", 1);
+        assert_eq!(
+            pow.verify(&key, &policy(8), &easier, "0", at(1)),
+            Err("forged challenge")
+        );
+        let solution = solve(&challenge);
+        assert_eq!(
+            pow.verify(
+                &key,
+                &policy(8),
+                &challenge,
+                &solution,
+                at(CHALLENGE_TTL.as_secs())
+            ),
+            Err("expired challenge")
+        );
+        assert!(pow.verify(&key, &policy(8), "garbage", "0", at(1)).is_err());
+    }
+}
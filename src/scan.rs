@@ -0,0 +1,317 @@
+/*
+ * Isabelle project
+ *
+ * Copyright 2023-2024 Maxim Menshikov
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining
+ * a copy of this software and associated documentation files (the “Software”),
+ * to deal in the Software without restriction, including without limitation
+ * the rights to use, copy, modify, merge, publish, distribute, sublicense,
+ * and/or sell copies of the Software, and to permit persons to whom the
+ * Software is furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included
+ * in all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS
+ * OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+ * FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+ * DEALINGS IN THE SOFTWARE.
+ */
+
+// Malware scanning of staged uploads before they reach permanent storage.
+// Backends are picked from the core settings item:
+//
+//   security_scan_backend      "" (default, no scanning) or "clamd"
+//   security_clamd_address     "unix:/run/clamav/clamd.ctl", a socket path,
+//                              or "host:port" (optionally "tcp:host:port")
+//   security_scan_fail_open    accept uploads when the scanner is
+//                              unreachable; default false
+//   security_scan_quarantine   keep infected files under
+//                              `<data_path>/quarantine/`; default true
+
+use isabelle_dm::data_model::item::Item;
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// clamd's reply to a whole file must arrive within this time.
+const SCAN_TIMEOUT: Duration = Duration::from_secs(60);
+const CHUNK_BYTES: usize = 64 * 1024;
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum Verdict {
+    Clean,
+    /// Signature name as reported by the scanner.
+    Infected(String),
+}
+
+pub(crate) trait MalwareScanner {
+    async fn scan(&self, path: &Path) -> io::Result<Verdict>;
+}
+
+/// Scanner selected by the settings.
+pub(crate) enum Scanner {
+    Clamd(Clamd),
+}
+
+impl Scanner {
+    /// Scanner configured in `settings`; `Ok(None)` when scanning is off.
+    /// A broken configuration is an error so it can't silently disable
+    /// scanning.
+    pub(crate) fn from_settings(settings: &Item) -> Result<Option<Self>, String> {
+        match settings.safe_str("security_scan_backend", "").as_str() {
+            "" | "none" => Ok(None),
+            "clamd" => {
+                let addr = settings.safe_str("security_clamd_address", "");
+                Clamd::parse(&addr).map(|c| Some(Scanner::Clamd(c)))
+            }
+            other => Err(format!("unknown scan backend {}", other)),
+        }
+    }
+}
+
+impl MalwareScanner for Scanner {
+    async fn scan(&self, path: &Path) -> io::Result<Verdict> {
+        match self {
+            Scanner::Clamd(c) => c.scan(path).await,
+        }
+    }
+}
+
+/// Client for clamd's `INSTREAM` command.
+#[derive(Debug, PartialEq)]
+pub(crate) enum Clamd {
+    Unix(String),
+    Tcp(String),
+}
+
+impl Clamd {
+    fn parse(addr: &str) -> Result<Self, String> {
+        if let Some(p) = addr.strip_prefix("unix:") {
+            return Ok(Clamd::Unix(p.to_string()));
+        }
+        if addr.starts_with('/') {
+            return Ok(Clamd::Unix(addr.to_string()));
+        }
+        let hostport = addr.strip_prefix("tcp:").unwrap_or(addr);
+        match hostport.rsplit_once(':') {
+            Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => {
+                Ok(Clamd::Tcp(hostport.to_string()))
+            }
+            _ => Err(format!("bad clamd address {:?}", addr)),
+        }
+    }
+
+    async fn scan_on<S: AsyncRead + AsyncWrite + Unpin>(
+        mut sock: S,
+        path: &Path,
+    ) -> io::Result<Verdict> {
+        // Null-terminated command; chunks are a 4-byte big-endian length
+        // followed by data, and an empty chunk ends the stream.
+        sock.write_all(b"zINSTREAM\0").await?;
+        let mut file = tokio::fs::File::open(path).await?;
+        let mut buf = vec![0u8; CHUNK_BYTES];
+        loop {
+            let n = file.read(&mut buf).await?;
+            sock.write_all(&(n as u32).to_be_bytes()).await?;
+            if n == 0 {
+                break;
+            }
+            sock.write_all(&buf[..n]).await?;
+        }
+        sock.flush().await?;
+
+        let mut reply = Vec::new();
+        sock.take(4096).read_to_end(&mut reply).await?;
+        let reply = String::from_utf8_lossy(&reply);
+        parse_reply(reply.trim_end_matches(['\0', '\n']))
+    }
+}
+
+impl MalwareScanner for Clamd {
+    async fn scan(&self, path: &Path) -> io::Result<Verdict> {
+        let scan = async {
+            match self {
+                Clamd::Unix(p) => {
+                    Self::scan_on(tokio::net::UnixStream::connect(p).await?, path).await
+                }
+                Clamd::Tcp(a) => {
+                    Self::scan_on(tokio::net::TcpStream::connect(a).await?, path).await
+                }
+            }
+        };
+        match tokio::time::timeout(SCAN_TIMEOUT, scan).await {
+            Ok(r) => r,
+            Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, "clamd timed out")),
+        }
+    }
+}
+
+/// `stream: OK`, `stream: <signature> FOUND` or `<message> ERROR`.
+fn parse_reply(reply: &str) -> io::Result<Verdict> {
+    let body = reply.strip_prefix("stream: ").unwrap_or(reply);
+    if body == "OK" {
+        return Ok(Verdict::Clean);
+    }
+    if let Some(sig) = body.strip_suffix(" FOUND") {
+        return Ok(Verdict::Infected(sig.to_string()));
+    }
+    Err(io::Error::other(format!("clamd: {}", reply)))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// The EICAR test file: harmless, but every scanner flags it.
+    pub(crate) const EICAR: &[u8] =
+        br"X5O!P%@AP[4\PZX54(P^)7CC)7}$EICAR-STANDARD-ANTIVIRUS-TEST-FILE!$H+H*";
+
+    /// Minimal clamd speaking INSTREAM: flags streams containing `EICAR`.
+    async fn serve<S: AsyncRead + AsyncWrite + Unpin>(mut sock: S) {
+        let mut cmd = [0u8; 10];
+        if sock.read_exact(&mut cmd).await.is_err() || &cmd != b"zINSTREAM\0" {
+            let _ = sock.write_all(b"UNKNOWN COMMAND\0").await;
+            return;
+        }
+        let mut data = Vec::new();
+        loop {
+            let mut len = [0u8; 4];
+            sock.read_exact(&mut len).await.unwrap();
+            let len = u32::from_be_bytes(len) as usize;
+            if len == 0 {
+                break;
+            }
+            let mut chunk = vec![0u8; len];
+            sock.read_exact(&mut chunk).await.unwrap();
+            data.extend_from_slice(&chunk);
+        }
+        let reply: &[u8] = if data.windows(EICAR.len()).any(|w| w == EICAR) {
+            b"stream: Eicar-Test-Signature FOUND\0"
+        } else {
+            b"stream: OK\0"
+        };
+        let _ = sock.write_all(reply).await;
+    }
+
+    /// Start a stub clamd on a Unix socket in `dir`; returns its address.
+    pub(crate) fn stub_clamd(dir: &Path) -> String {
+        let path = dir.join("clamd.sock");
+        let listener = tokio::net::UnixListener::bind(&path).unwrap();
+        tokio::spawn(async move {
+            while let Ok((sock, _)) = listener.accept().await {
+                tokio::spawn(serve(sock));
+            }
+        });
+        format!("unix:{}", path.display())
+    }
+
+    /// Start a clamd on a Unix socket in `dir` that accepts connections and
+    /// never answers; returns its address.
+    pub(crate) fn stalled_clamd(dir: &Path) -> String {
+        let path = dir.join("stalled.sock");
+        let listener = tokio::net::UnixListener::bind(&path).unwrap();
+        tokio::spawn(async move {
+            let mut held = Vec::new();
+            while let Ok((sock, _)) = listener.accept().await {
+                held.push(sock);
+            }
+        });
+        format!("unix:{}", path.display())
+    }
+
+    #[test]
+    fn parse_addresses() {
+        assert_eq!(
+            Clamd::parse("unix:/run/c.sock"),
+            Ok(Clamd::Unix("/run/c.sock".into()))
+        );
+        assert_eq!(
+            Clamd::parse("/run/c.sock"),
+            Ok(Clamd::Unix("/run/c.sock".into()))
+        );
+        assert_eq!(
+            Clamd::parse("tcp:av:3310"),
+            Ok(Clamd::Tcp("av:3310".into()))
+        );
+        assert_eq!(
+            Clamd::parse("127.0.0.1:3310"),
+            Ok(Clamd::Tcp("127.0.0.1:3310".into()))
+        );
+        assert!(Clamd::parse("").is_err());
+        assert!(Clamd::parse("av:port").is_err());
+    }
+
+    #[test]
+    fn settings_select_backend() {
+        let mut settings = Item::new();
+        assert!(matches!(Scanner::from_settings(&settings), Ok(None)));
+        settings.set_str("security_scan_backend", "clamd");
+        assert!(Scanner::from_settings(&settings).is_err());
+        settings.set_str("security_clamd_address", "localhost:3310");
+        assert!(matches!(
+            Scanner::from_settings(&settings),
+            Ok(Some(Scanner::Clamd(_)))
+        ));
+        settings.set_str("security_scan_backend", "magic");
+        assert!(Scanner::from_settings(&settings).is_err());
+    }
+
+    #[test]
+    fn parse_replies() {
+        assert_eq!(parse_reply("stream: OK").unwrap(), Verdict::Clean);
+        assert_eq!(
+            parse_reply("stream: Win.Test.EICAR_HDB-1 FOUND").unwrap(),
+            Verdict::Infected("Win.Test.EICAR_HDB-1".into())
+        );
+        assert!(parse_reply("INSTREAM size limit exceeded. ERROR").is_err());
+        assert!(parse_reply("").is_err());
+    }
+
+    #[tokio::test]
+    async fn scans_over_unix_and_tcp() {
+        let dir = tempfile::tempdir().unwrap();
+        let clean = dir.path().join("clean");
+        let infected = dir.path().join("infected");
+        // Larger than one chunk, with the signature straddling a boundary.
+        let mut data = vec![b'a'; CHUNK_BYTES - 10];
+        std::fs::write(&clean, &data).unwrap();
+        data.extend_from_slice(EICAR);
+        std::fs::write(&infected, &data).unwrap();
+
+        let unix = Clamd::parse(&stub_clamd(dir.path())).unwrap();
+        assert_eq!(unix.scan(&clean).await.unwrap(), Verdict::Clean);
+        assert!(matches!(
+            unix.scan(&infected).await.unwrap(),
+            Verdict::Infected(_)
+        ));
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((sock, _)) = listener.accept().await {
+                tokio::spawn(serve(sock));
+            }
+        });
+        let tcp = Clamd::parse(&addr.to_string()).unwrap();
+        assert_eq!(tcp.scan(&clean).await.unwrap(), Verdict::Clean);
+        assert!(matches!(
+            tcp.scan(&infected).await.unwrap(),
+            Verdict::Infected(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn unreachable_daemon_is_an_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let f = dir.path().join("f");
+        std::fs::write(&f, b"x").unwrap();
+        let c = Clamd::Unix(dir.path().join("missing.sock").display().to_string());
+        assert!(c.scan(&f).await.is_err());
+    }
+}
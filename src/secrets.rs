@@ -0,0 +1,244 @@
+/*
+ * Isabelle project
+ *
+ * Copyright 2023-2024 Maxim Menshikov
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining
+ * a copy of this software and associated documentation files (the “Software”),
+ * to deal in the Software without restriction, including without limitation
+ * the rights to use, copy, modify, merge, publish, distribute, sublicense,
+ * and/or sell copies of the Software, and to permit persons to whom the
+ * Software is furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included
+ * in all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS
+ * OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+ * FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+ * DEALINGS IN THE SOFTWARE.
+ */
+
+// Encryption of secrets the plugin has to read back (TOTP seeds), with
+// ChaCha20-Poly1305 under a 32-byte master key. The key comes from the
+// `security_secret_key` setting (64 hex characters) or, if that is unset,
+// from `<data_path>/security-secret.key`, which is created on first use.
+// Losing the key means every enrolled user has to enroll again.
+
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use hmac::{Hmac, Mac};
+use isabelle_dm::data_model::item::Item;
+use sha2::Sha256;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+const KEY_FILE: &str = "security-secret.key";
+const PREFIX: &str = "v1:";
+
+pub(crate) struct SecretKey([u8; 32]);
+
+/// Fill `buf` from the operating system's CSPRNG.
+pub(crate) fn random_bytes(buf: &mut [u8]) -> io::Result<()> {
+    getrandom::getrandom(buf).map_err(|e| io::Error::other(e.to_string()))
+}
+
+pub(crate) fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub(crate) fn from_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+impl SecretKey {
+    pub(crate) fn load(settings: &Item, data_path: &str) -> io::Result<Self> {
+        let configured = settings.safe_str("security_secret_key", "");
+        if !configured.is_empty() {
+            return Self::from_hex_str(&configured).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "security_secret_key must be 64 hex characters",
+                )
+            });
+        }
+        let path = Path::new(data_path).join(KEY_FILE);
+        match fs::read_to_string(&path) {
+            Ok(s) => Self::from_hex_str(s.trim()).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{} is corrupt", path.display()),
+                )
+            }),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let mut key = [0u8; 32];
+                random_bytes(&mut key)?;
+                write_key_file(&path, &to_hex(&key))?;
+                // Another request may have won the race; use whatever
+                // ended up on disk.
+                let s = fs::read_to_string(&path)?;
+                Self::from_hex_str(s.trim())
+                    .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "bad key file"))
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    fn from_hex_str(s: &str) -> Option<Self> {
+        from_hex(s)?.try_into().ok().map(SecretKey)
+    }
+
+    /// Encrypt `plaintext`, bound to `context` (e.g. the owning user), so a
+    /// ciphertext copied to another context fails to open.
+    pub(crate) fn seal(&self, context: &str, plaintext: &[u8]) -> io::Result<String> {
+        let mut nonce = [0u8; 12];
+        random_bytes(&mut nonce)?;
+        let ct = ChaCha20Poly1305::new(Key::from_slice(&self.0))
+            .encrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: plaintext,
+                    aad: context.as_bytes(),
+                },
+            )
+            .map_err(|_| io::Error::other("encryption failed"))?;
+        Ok(format!("{}{}{}", PREFIX, to_hex(&nonce), to_hex(&ct)))
+    }
+
+    pub(crate) fn open(&self, context: &str, sealed: &str) -> Option<Vec<u8>> {
+        let raw = from_hex(sealed.strip_prefix(PREFIX)?)?;
+        if raw.len() < 12 {
+            return None;
+        }
+        let (nonce, ct) = raw.split_at(12);
+        ChaCha20Poly1305::new(Key::from_slice(&self.0))
+            .decrypt(
+                Nonce::from_slice(nonce),
+                Payload {
+                    msg: ct,
+                    aad: context.as_bytes(),
+                },
+            )
+            .ok()
+    }
+
+    /// HMAC-SHA256 of `data` bound to `context`, under a key derived from
+    /// the master key so signing and encryption never share a key.
+    fn mac(&self, context: &str, data: &str) -> Hmac<Sha256> {
+        let mut derive =
+            <Hmac<Sha256> as Mac>::new_from_slice(&self.0).expect("HMAC takes any key size");
+        derive.update(b"isabelle-security-mac-v1");
+        let subkey = derive.finalize().into_bytes();
+        let mut mac =
+            <Hmac<Sha256> as Mac>::new_from_slice(&subkey).expect("HMAC takes any key size");
+        mac.update(context.as_bytes());
+        mac.update(&[0]);
+        mac.update(data.as_bytes());
+        mac
+    }
+
+    /// Hex signature of `data` for `context`, for tokens handed to clients.
+    pub(crate) fn sign(&self, context: &str, data: &str) -> String {
+        to_hex(&self.mac(context, data).finalize().into_bytes())
+    }
+
+    /// Whether `sig` is the signature of `data` for `context`; constant time.
+    pub(crate) fn verify(&self, context: &str, data: &str, sig: &str) -> bool {
+        match from_hex(sig) {
+            Some(sig) => self.mac(context, data).verify_slice(&sig).is_ok(),
+            None => false,
+        }
+    }
+}
+
+/// Create the key file readable by the owner only; never overwrite one.
+fn write_key_file(path: &Path, contents: &str) -> io::Result<()> {
+    let mut opts = fs::OpenOptions::new();
+    opts.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut opts, 0o600);
+    match opts.open(path) {
+        Ok(mut f) => {
+            io::Write::write_all(&mut f, contents.as_bytes())?;
+            f.sync_all()
+        }
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hex_round_trip() {
+        assert_eq!(to_hex(&[0, 15, 255]), "000fff");
+        assert_eq!(from_hex("000fff"), Some(vec![0, 15, 255]));
+        assert_eq!(from_hex("0"), None);
+        assert_eq!(from_hex("zz"), None);
+    }
+
+    #[test]
+    fn key_file_created_once_and_reused() {
+        let dir = tempfile::tempdir().unwrap();
+        let dp = dir.path().to_str().unwrap();
+        let a = SecretKey::load(&Item::new(), dp).unwrap();
+        let b = SecretKey::load(&Item::new(), dp).unwrap();
+        assert_eq!(a.0, b.0);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(dir.path().join(KEY_FILE))
+                .unwrap()
+                .permissions()
+                .mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        let mut settings = Item::new();
+        settings.set_str("security_secret_key", &"ab".repeat(32));
+        assert_eq!(SecretKey::load(&settings, dp).unwrap().0, [0xab; 32]);
+        settings.set_str("security_secret_key", "short");
+        assert!(SecretKey::load(&settings, dp).is_err());
+    }
+
+    #[test]
+    fn signatures_bound_to_key_and_context() {
+        let key = SecretKey([7; 32]);
+        let sig = key.sign("magic", "1.2.3");
+        assert_eq!(sig.len(), 64);
+        assert!(key.verify("magic", "1.2.3", &sig));
+        assert!(!key.verify("magic", "1.2.4", &sig));
+        assert!(!key.verify("other", "1.2.3", &sig));
+        assert!(!key.verify("magic", "1.2.3", &sig[..62]));
+        assert!(!key.verify("magic", "1.2.3", "zz"));
+        assert!(!SecretKey([8; 32]).verify("magic", "1.2.3", &sig));
+    }
+
+    #[test]
+    fn seal_is_authenticated_and_bound_to_context() {
+        let key = SecretKey([7; 32]);
+        let sealed = key.seal("totp:1", b"secret").unwrap();
+        assert!(!sealed.contains(&to_hex(b"secret")));
+        assert_eq!(key.open("totp:1", &sealed).unwrap(), b"secret");
+        assert!(key.open("totp:2", &sealed).is_none());
+        assert!(SecretKey([8; 32]).open("totp:1", &sealed).is_none());
+        let mut tampered = sealed.clone();
+        tampered.pop();
+        tampered.push(if sealed.ends_with('0') { '1' } else { '0' });
+        assert!(key.open("totp:1", &tampered).is_none());
+        // Fresh nonce every time.
+        assert_ne!(key.seal("totp:1", b"secret").unwrap(), sealed);
+    }
+}
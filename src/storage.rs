@@ -0,0 +1,413 @@
+/*
+ * Isabelle project
+ *
+ * Copyright 2023-2024 Maxim Menshikov
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining
+ * a copy of this software and associated documentation files (the “Software”),
+ * to deal in the Software without restriction, including without limitation
+ * the rights to use, copy, modify, merge, publish, distribute, sublicense,
+ * and/or sell copies of the Software, and to permit persons to whom the
+ * Software is furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included
+ * in all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS
+ * OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+ * FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+ * DEALINGS IN THE SOFTWARE.
+ */
+
+// Object storage for plugin-managed files (avatars). Objects are addressed
+// by a flat key such as `<sha256>.png`; where they end up is the backend's
+// business. Backends are picked from the core settings item:
+//
+//   security_storage_backend   "local" (default) or "s3"
+//   security_s3_endpoint       e.g. "http://minio:9000" (path-style access)
+//   security_s3_bucket
+//   security_s3_region         default "us-east-1"
+//   security_s3_access_key
+//   security_s3_secret_key
+//   security_s3_prefix         optional key prefix inside the bucket
+
+mod s3;
+
+use isabelle_dm::data_model::item::Item;
+use log::error;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::SystemTime;
+
+pub(crate) use s3::S3Storage;
+
+pub(crate) trait FileStorage {
+    /// Store `data` under `key`, replacing any existing object. Readers see
+    /// either the old or the new content, never a partial write.
+    async fn put(&self, key: &str, data: &[u8]) -> io::Result<()>;
+
+    async fn exists(&self, key: &str) -> io::Result<bool>;
+
+    /// Path of a local file holding the object, suitable for
+    /// `WebResponse::OkFilePath`, or `None` if the object doesn't exist.
+    async fn local_path(&self, key: &str) -> io::Result<Option<PathBuf>>;
+
+    /// Remove the object. Removing a missing object is not an error.
+    async fn delete(&self, key: &str) -> io::Result<()>;
+
+    /// Every stored object, for housekeeping sweeps.
+    async fn list(&self) -> io::Result<Vec<StoredObject>>;
+}
+
+pub(crate) struct StoredObject {
+    pub(crate) key: String,
+    pub(crate) modified: SystemTime,
+}
+
+/// Storage backend selected by the settings.
+pub(crate) enum Storage {
+    Local(LocalStorage),
+    S3(Box<S3Storage>),
+}
+
+impl Storage {
+    /// Backend for the files of `area` (e.g. "user-avatars"). Returns `None`
+    /// for an unusable configuration rather than silently writing somewhere
+    /// else than the operator asked for.
+    pub(crate) fn from_settings(settings: &Item, data_path: &str, area: &str) -> Option<Self> {
+        match settings
+            .safe_str("security_storage_backend", "local")
+            .as_str()
+        {
+            "" | "local" => Some(Storage::Local(LocalStorage::new(
+                Path::new(data_path).join(area),
+            ))),
+            "s3" => {
+                let endpoint = settings.safe_str("security_s3_endpoint", "");
+                let bucket = settings.safe_str("security_s3_bucket", "");
+                if endpoint.is_empty() || bucket.is_empty() {
+                    error!("S3 storage selected but endpoint or bucket is not set");
+                    return None;
+                }
+                let prefix = format!("{}{}/", settings.safe_str("security_s3_prefix", ""), area);
+                S3Storage::new(
+                    &endpoint,
+                    &bucket,
+                    &settings.safe_str("security_s3_region", "us-east-1"),
+                    &settings.safe_str("security_s3_access_key", ""),
+                    &settings.safe_str("security_s3_secret_key", ""),
+                    &prefix,
+                    Path::new(data_path).join(format!("{}-cache", area)),
+                )
+                .map(|s| Storage::S3(Box::new(s)))
+                .map_err(|e| error!("Bad S3 storage configuration: {}", e))
+                .ok()
+            }
+            other => {
+                error!("Unknown storage backend {}", other);
+                None
+            }
+        }
+    }
+}
+
+impl Storage {
+    /// See `LocalStorage::adopt`; other backends have no legacy layout.
+    pub(crate) fn adopt(&self, key: &str) -> io::Result<bool> {
+        match self {
+            Storage::Local(s) => s.adopt(key),
+            Storage::S3(_) => Ok(false),
+        }
+    }
+}
+
+impl FileStorage for Storage {
+    async fn put(&self, key: &str, data: &[u8]) -> io::Result<()> {
+        match self {
+            Storage::Local(s) => s.put(key, data).await,
+            Storage::S3(s) => s.put(key, data).await,
+        }
+    }
+
+    async fn exists(&self, key: &str) -> io::Result<bool> {
+        match self {
+            Storage::Local(s) => s.exists(key).await,
+            Storage::S3(s) => s.exists(key).await,
+        }
+    }
+
+    async fn local_path(&self, key: &str) -> io::Result<Option<PathBuf>> {
+        match self {
+            Storage::Local(s) => s.local_path(key).await,
+            Storage::S3(s) => s.local_path(key).await,
+        }
+    }
+
+    async fn delete(&self, key: &str) -> io::Result<()> {
+        match self {
+            Storage::Local(s) => s.delete(key).await,
+            Storage::S3(s) => s.delete(key).await,
+        }
+    }
+
+    async fn list(&self) -> io::Result<Vec<StoredObject>> {
+        match self {
+            Storage::Local(s) => s.list().await,
+            Storage::S3(s) => s.list().await,
+        }
+    }
+}
+
+/// Keys become file names and URL path segments, so only a conservative
+/// character set is accepted.
+pub(crate) fn check_key(key: &str) -> io::Result<()> {
+    let ok = !key.is_empty()
+        && !key.starts_with('.')
+        && key
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'.' | b'-' | b'_'));
+    if ok {
+        Ok(())
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid storage key {:?}", key),
+        ))
+    }
+}
+
+/// Write `data` to `path` through a temporary file in the same directory
+/// and a rename, so a crash never leaves a truncated file behind.
+pub(crate) fn write_atomic(path: &Path, data: &[u8]) -> io::Result<()> {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let dir = path.parent().unwrap_or(Path::new("."));
+    fs::create_dir_all(dir)?;
+    let tmp = dir.join(format!(
+        ".{}.{}.{}.tmp",
+        path.file_name().and_then(|n| n.to_str()).unwrap_or("obj"),
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    let res = fs::File::create(&tmp).and_then(|mut f| {
+        io::Write::write_all(&mut f, data)?;
+        f.sync_all()
+    });
+    if let Err(e) = res.and_then(|_| fs::rename(&tmp, path)) {
+        let _ = fs::remove_file(&tmp);
+        return Err(e);
+    }
+    Ok(())
+}
+
+/// Files under `<root>/<k0k1>/<k2k3>/<key>`, so no single directory grows
+/// with the number of users.
+pub(crate) struct LocalStorage {
+    root: PathBuf,
+}
+
+impl LocalStorage {
+    pub(crate) fn new(root: PathBuf) -> Self {
+        LocalStorage { root }
+    }
+
+    fn path(&self, key: &str) -> io::Result<PathBuf> {
+        check_key(key)?;
+        if key.len() < 4 || !key[0..4].bytes().all(|b| b.is_ascii_alphanumeric()) {
+            return Ok(self.root.join(key));
+        }
+        Ok(self.root.join(&key[0..2]).join(&key[2..4]).join(key))
+    }
+
+    /// Move an object written before sharding, directly in the root, into
+    /// place. Returns whether there was one to move.
+    pub(crate) fn adopt(&self, key: &str) -> io::Result<bool> {
+        let path = self.path(key)?;
+        let flat = self.root.join(key);
+        if flat == path || !flat.is_file() {
+            return Ok(false);
+        }
+        if path.exists() {
+            fs::remove_file(&flat)?;
+        } else {
+            fs::create_dir_all(path.parent().unwrap_or(&self.root))?;
+            fs::rename(&flat, &path)?;
+        }
+        Ok(true)
+    }
+}
+
+impl FileStorage for LocalStorage {
+    async fn put(&self, key: &str, data: &[u8]) -> io::Result<()> {
+        write_atomic(&self.path(key)?, data)
+    }
+
+    async fn exists(&self, key: &str) -> io::Result<bool> {
+        Ok(self.local_path(key).await?.is_some())
+    }
+
+    async fn local_path(&self, key: &str) -> io::Result<Option<PathBuf>> {
+        let path = self.path(key)?;
+        if path.exists() {
+            return Ok(Some(path));
+        }
+        // Objects written before sharding sit directly in the root until
+        // `adopt` moves them.
+        let flat = self.root.join(key);
+        if flat.is_file() {
+            return Ok(Some(flat));
+        }
+        Ok(None)
+    }
+
+    async fn delete(&self, key: &str) -> io::Result<()> {
+        for path in [self.path(key)?, self.root.join(key)] {
+            match fs::remove_file(path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Walks the shard directories only: files directly in the root are
+    /// not objects (or not yet adopted ones) and temporary files are hidden.
+    async fn list(&self) -> io::Result<Vec<StoredObject>> {
+        let mut out = Vec::new();
+        for level1 in read_dirs(&self.root)? {
+            for level2 in read_dirs(&level1)? {
+                for entry in fs::read_dir(&level2)? {
+                    let entry = entry?;
+                    let key = entry.file_name().to_string_lossy().to_string();
+                    if check_key(&key).is_err() || !entry.file_type()?.is_file() {
+                        continue;
+                    }
+                    let modified = entry.metadata()?.modified()?;
+                    out.push(StoredObject { key, modified });
+                }
+            }
+        }
+        Ok(out)
+    }
+}
+
+/// Subdirectories of `dir`; a missing `dir` has none.
+fn read_dirs(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let entries = match fs::read_dir(dir) {
+        Ok(e) => e,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let mut out = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            out.push(entry.path());
+        }
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_key_rejects_path_tricks() {
+        assert!(check_key("abcd.png").is_ok());
+        assert!(check_key("").is_err());
+        assert!(check_key("..").is_err());
+        assert!(check_key(".hidden").is_err());
+        assert!(check_key("a/b").is_err());
+        assert!(check_key("a\\b").is_err());
+        assert!(check_key("a%2fb").is_err());
+    }
+
+    #[tokio::test]
+    async fn local_storage_shards_and_round_trips() {
+        let dir = tempfile::tempdir().unwrap();
+        let st = LocalStorage::new(dir.path().to_path_buf());
+        assert!(!st.exists("abcdef.png").await.unwrap());
+        st.put("abcdef.png", b"one").await.unwrap();
+        let p = st.local_path("abcdef.png").await.unwrap().unwrap();
+        assert_eq!(p, dir.path().join("ab/cd/abcdef.png"));
+        assert_eq!(fs::read(&p).unwrap(), b"one");
+
+        st.put("abcdef.png", b"two").await.unwrap();
+        assert_eq!(fs::read(&p).unwrap(), b"two");
+        // No temporary files are left next to the object.
+        assert_eq!(fs::read_dir(dir.path().join("ab/cd")).unwrap().count(), 1);
+        assert!(st.exists("abcdef.png").await.unwrap());
+        assert!(st.put("../x", b"").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn local_storage_adopts_flat_layout() {
+        let dir = tempfile::tempdir().unwrap();
+        let flat = dir.path().join("abcdef.png");
+        fs::write(&flat, b"old").unwrap();
+        let st = LocalStorage::new(dir.path().to_path_buf());
+        // Reading finds the flat object but leaves it where it is.
+        assert_eq!(
+            st.local_path("abcdef.png").await.unwrap(),
+            Some(flat.clone())
+        );
+        assert!(flat.exists());
+
+        assert!(st.adopt("abcdef.png").unwrap());
+        assert!(!flat.exists());
+        let p = st.local_path("abcdef.png").await.unwrap().unwrap();
+        assert_eq!(p, dir.path().join("ab/cd/abcdef.png"));
+        assert_eq!(fs::read(&p).unwrap(), b"old");
+        assert!(!st.adopt("abcdef.png").unwrap());
+    }
+
+    #[tokio::test]
+    async fn local_storage_lists_and_deletes_objects_only() {
+        let dir = tempfile::tempdir().unwrap();
+        let st = LocalStorage::new(dir.path().to_path_buf());
+        assert!(st.list().await.unwrap().is_empty());
+        st.put("abcdef.png", b"one").await.unwrap();
+        st.put("abcxyz.png", b"two").await.unwrap();
+        fs::write(dir.path().join("7.stage"), b"").unwrap();
+        fs::write(dir.path().join("ab/cd/.abcdef.png.1.2.tmp"), b"").unwrap();
+
+        let mut keys: Vec<String> = st
+            .list()
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|o| o.key)
+            .collect();
+        keys.sort();
+        assert_eq!(keys, ["abcdef.png", "abcxyz.png"]);
+
+        st.delete("abcdef.png").await.unwrap();
+        st.delete("abcdef.png").await.unwrap();
+        assert!(!st.exists("abcdef.png").await.unwrap());
+        assert_eq!(st.list().await.unwrap().len(), 1);
+    }
+
+    #[test]
+    fn from_settings_picks_backend() {
+        let mut settings = Item::new();
+        assert!(matches!(
+            Storage::from_settings(&settings, "/data", "user-avatars"),
+            Some(Storage::Local(_))
+        ));
+        settings.set_str("security_storage_backend", "s3");
+        assert!(Storage::from_settings(&settings, "/data", "user-avatars").is_none());
+        settings.set_str("security_s3_endpoint", "http://127.0.0.1:9000");
+        settings.set_str("security_s3_bucket", "isabelle");
+        assert!(matches!(
+            Storage::from_settings(&settings, "/data", "user-avatars"),
+            Some(Storage::S3(_))
+        ));
+        settings.set_str("security_storage_backend", "ftp");
+        assert!(Storage::from_settings(&settings, "/data", "user-avatars").is_none());
+    }
+}
@@ -0,0 +1,276 @@
+/*
+ * Isabelle project
+ *
+ * Copyright 2023-2024 Maxim Menshikov
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining
+ * a copy of this software and associated documentation files (the “Software”),
+ * to deal in the Software without restriction, including without limitation
+ * the rights to use, copy, modify, merge, publish, distribute, sublicense,
+ * and/or sell copies of the Software, and to permit persons to whom the
+ * Software is furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included
+ * in all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS
+ * OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+ * FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+ * DEALINGS IN THE SOFTWARE.
+ */
+
+// RFC 6238 time-based one-time passwords with the parameters every
+// authenticator app supports: HMAC-SHA1, 6 digits, 30-second steps.
+
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+
+pub(crate) const DIGITS: u32 = 6;
+pub(crate) const STEP_SECS: u64 = 30;
+/// Codes from this many steps before or after the current one are accepted,
+/// to allow for clock drift and typing time.
+const WINDOW: u64 = 1;
+pub(crate) const SECRET_BYTES: usize = 20;
+
+const BASE32: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+/// RFC 4648 base32 without padding, the form otpauth URIs carry.
+pub(crate) fn base32_encode(data: &[u8]) -> String {
+    let mut out = String::new();
+    for chunk in data.chunks(5) {
+        let mut buf = [0u8; 5];
+        buf[..chunk.len()].copy_from_slice(chunk);
+        let bits = u64::from_be_bytes([0, 0, 0, buf[0], buf[1], buf[2], buf[3], buf[4]]);
+        let chars = (chunk.len() * 8).div_ceil(5);
+        for i in 0..chars {
+            out.push(BASE32[((bits >> (35 - i * 5)) & 31) as usize] as char);
+        }
+    }
+    out
+}
+
+/// RFC 4648 HOTP value of `secret` for `counter`.
+pub(crate) fn hotp(secret: &[u8], counter: u64, digits: u32) -> String {
+    let mut mac = <Hmac<Sha1> as Mac>::new_from_slice(secret).expect("HMAC takes any key size");
+    mac.update(&counter.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let bin = u32::from_be_bytes([
+        hash[offset] & 0x7f,
+        hash[offset + 1],
+        hash[offset + 2],
+        hash[offset + 3],
+    ]);
+    format!(
+        "{:0width$}",
+        bin % 10u32.pow(digits),
+        width = digits as usize
+    )
+}
+
+pub(crate) fn step_at(unix_secs: u64) -> u64 {
+    unix_secs / STEP_SECS
+}
+
+/// Time step `code` is valid for at `unix_secs`, if any. Callers must
+/// reject steps at or before the last one accepted, or a code could be
+/// replayed within its window.
+pub(crate) fn verify(secret: &[u8], code: &str, unix_secs: u64) -> Option<u64> {
+    let code = code.trim();
+    if code.len() != DIGITS as usize || !code.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let now = step_at(unix_secs);
+    let mut found = None;
+    // Check every candidate so timing doesn't reveal which one matched.
+    for step in now.saturating_sub(WINDOW)..=now + WINDOW {
+        if crate::constant_time_eq(&hotp(secret, step, DIGITS), code) && found.is_none() {
+            found = Some(step);
+        }
+    }
+    found
+}
+
+/// Recovery codes handed out per enrollment, and base32 characters in each.
+pub(crate) const RECOVERY_CODES: usize = 10;
+const RECOVERY_CHARS: usize = 10;
+
+/// A recovery code from 8 random bytes (50 bits are used), grouped as
+/// `XXXXX-XXXXX` for reading off a printout.
+pub(crate) fn recovery_code(random: &[u8; 8]) -> String {
+    let enc = base32_encode(random);
+    format!(
+        "{}-{}",
+        &enc[..RECOVERY_CHARS / 2],
+        &enc[RECOVERY_CHARS / 2..RECOVERY_CHARS]
+    )
+}
+
+/// Canonical form of a typed recovery code, the one that gets hashed:
+/// case, dashes and spaces don't matter. `None` if it can't be one.
+pub(crate) fn normalize_recovery(code: &str) -> Option<String> {
+    let code: String = code
+        .chars()
+        .filter(|c| *c != '-' && !c.is_whitespace())
+        .map(|c| c.to_ascii_uppercase())
+        .collect();
+    if code.len() != RECOVERY_CHARS || !code.bytes().all(|b| BASE32.contains(&b)) {
+        return None;
+    }
+    Some(code)
+}
+
+/// `otpauth://` URI for enrolling `secret` in an authenticator app.
+pub(crate) fn otpauth_uri(issuer: &str, account: &str, secret: &[u8]) -> String {
+    let enc = |s: &str| -> String {
+        s.bytes()
+            .map(|b| match b {
+                b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                    (b as char).to_string()
+                }
+                _ => format!("%{:02X}", b),
+            })
+            .collect()
+    };
+    format!(
+        "otpauth://totp/{}:{}?secret={}&issuer={}&algorithm=SHA1&digits={}&period={}",
+        enc(issuer),
+        enc(account),
+        base32_encode(secret),
+        enc(issuer),
+        DIGITS,
+        STEP_SECS
+    )
+}
+
+/// Pixels per QR module, and modules of quiet zone around the code.
+const QR_SCALE: u32 = 8;
+const QR_QUIET: u32 = 4;
+
+/// `data` as a black-on-white QR code image, ready for PNG encoding.
+pub(crate) fn render_qr(data: &str) -> Result<image::RgbaImage, qrcode::types::QrError> {
+    let code = qrcode::QrCode::new(data.as_bytes())?;
+    let width = code.width() as u32;
+    let colors = code.to_colors();
+    let size = (width + 2 * QR_QUIET) * QR_SCALE;
+    Ok(image::RgbaImage::from_fn(size, size, |x, y| {
+        let (mx, my) = (x / QR_SCALE, y / QR_SCALE);
+        let dark = mx >= QR_QUIET
+            && my >= QR_QUIET
+            && mx < width + QR_QUIET
+            && my < width + QR_QUIET
+            && colors[((my - QR_QUIET) * width + mx - QR_QUIET) as usize] == qrcode::Color::Dark;
+        if dark {
+            image::Rgba([0, 0, 0, 255])
+        } else {
+            image::Rgba([255, 255, 255, 255])
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// RFC 6238 appendix B seed for SHA1.
+    const SEED: &[u8] = b"12345678901234567890";
+
+    #[test]
+    fn rfc6238_sha1_vectors() {
+        for (t, expected) in [
+            (59, "94287082"),
+            (1111111109, "07081804"),
+            (1111111111, "14050471"),
+            (1234567890, "89005924"),
+            (2000000000, "69279037"),
+            (20000000000, "65353130"),
+        ] {
+            assert_eq!(hotp(SEED, step_at(t), 8), expected, "T={}", t);
+        }
+    }
+
+    #[test]
+    fn rfc4226_hotp_vectors() {
+        let expected = ["755224", "287082", "359152", "969429", "338314"];
+        for (counter, code) in expected.iter().enumerate() {
+            assert_eq!(hotp(SEED, counter as u64, 6), *code);
+        }
+    }
+
+    #[test]
+    fn verify_accepts_window_only() {
+        let t = 1111111111;
+        let now = hotp(SEED, step_at(t), DIGITS);
+        assert_eq!(verify(SEED, &now, t), Some(step_at(t)));
+        let prev = hotp(SEED, step_at(t) - 1, DIGITS);
+        assert_eq!(verify(SEED, &prev, t), Some(step_at(t) - 1));
+        let old = hotp(SEED, step_at(t) - 2, DIGITS);
+        assert_eq!(verify(SEED, &old, t), None);
+        assert_eq!(verify(SEED, "12345", t), None);
+        assert_eq!(verify(SEED, "abcdef", t), None);
+    }
+
+    #[test]
+    fn base32_rfc4648_vectors() {
+        for (input, expected) in [
+            ("", ""),
+            ("f", "MY"),
+            ("fo", "MZXQ"),
+            ("foo", "MZXW6"),
+            ("foob", "MZXW6YQ"),
+            ("fooba", "MZXW6YTB"),
+            ("foobar", "MZXW6YTBOI"),
+        ] {
+            assert_eq!(base32_encode(input.as_bytes()), expected);
+        }
+        assert_eq!(base32_encode(SEED), "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ");
+    }
+
+    #[test]
+    fn qr_has_quiet_zone_and_finder_patterns() {
+        let uri = otpauth_uri("Isabelle", "alice@example.com", SEED);
+        let img = render_qr(&uri).unwrap();
+        let modules = img.width() / QR_SCALE;
+        assert_eq!(img.width(), img.height());
+        assert_eq!(img.width() % QR_SCALE, 0);
+        let dark = |mx: u32, my: u32| img.get_pixel(mx * QR_SCALE, my * QR_SCALE)[0] == 0;
+        let q = QR_QUIET;
+        assert!(!dark(0, 0));
+        assert!(!dark(q - 1, q - 1));
+        // Top-left, top-right and bottom-left finders: dark 7x7 ring, light
+        // ring inside, dark 3x3 center.
+        for (ox, oy) in [(q, q), (modules - q - 7, q), (q, modules - q - 7)] {
+            assert!(dark(ox, oy) && dark(ox + 6, oy + 6));
+            assert!(!dark(ox + 1, oy + 1));
+            assert!(dark(ox + 3, oy + 3));
+        }
+    }
+
+    #[test]
+    fn recovery_codes_normalize() {
+        let code = recovery_code(&[0xff; 8]);
+        assert_eq!(code, "77777-77777");
+        assert_eq!(normalize_recovery(&code).unwrap(), "7777777777");
+        assert_eq!(normalize_recovery(" abcde 23456\n").unwrap(), "ABCDE23456");
+        assert_eq!(normalize_recovery("123456"), None);
+        // Digits 0, 1, 8 and 9 are not in the alphabet.
+        assert_eq!(normalize_recovery("ABCDE-2345Z0"), None);
+        assert_eq!(normalize_recovery("ABCDE-23451"), None);
+        assert_eq!(
+            recovery_code(&[0, 0, 0, 0, 0, 0, 0, 0]).len(),
+            RECOVERY_CHARS + 1
+        );
+    }
+
+    #[test]
+    fn otpauth_uri_escapes_labels() {
+        assert_eq!(
+            otpauth_uri("Isabelle", "alice@example.com", SEED),
+            "otpauth://totp/Isabelle:alice%40example.com?secret=GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ\
+             &issuer=Isabelle&algorithm=SHA1&digits=6&period=30"
+        );
+    }
+}
@@ -0,0 +1,353 @@
+/*
+ * Isabelle project
+ *
+ * Copyright 2023-2024 Maxim Menshikov
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining
+ * a copy of this software and associated documentation files (the “Software”),
+ * to deal in the Software without restriction, including without limitation
+ * the rights to use, copy, modify, merge, publish, distribute, sublicense,
+ * and/or sell copies of the Software, and to permit persons to whom the
+ * Software is furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included
+ * in all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS
+ * OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+ * FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+ * DEALINGS IN THE SOFTWARE.
+ */
+
+// Hardened handling of uploaded files, shared by avatars and the generic
+// per-collection uploads. An upload is moved to a fixed staging name (the
+// client's file name never reaches the file system), size-checked, typed by
+// content rather than by name or declared MIME type and, for images,
+// decoded and re-encoded so only pixels survive. Collections opt in through
+// the settings item:
+//
+//   security_upload_collections            comma-separated, e.g. "company,document"
+//   security_upload_<collection>_max_bytes default 10 MiB
+//   security_upload_<collection>_mime      allowed types, default "image/png,image/jpeg"
+//   security_upload_<collection>_owner     u64 field of the target item
+//                                          holding the owning user id,
+//                                          default "owner"
+
+use image::ImageEncoder;
+use isabelle_dm::data_model::item::Item;
+use std::collections::HashMap;
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use tokio::sync::Semaphore;
+
+/// Uploaded images wider/taller than this are rejected by the decoder.
+pub(crate) const MAX_IMAGE_DIMENSION: u32 = 8192;
+/// Decoder allocations for one image are capped at this many bytes, so a
+/// small, highly compressed file can't expand into gigabytes of pixels.
+pub(crate) const MAX_IMAGE_ALLOC_BYTES: u64 = 128 * 1024 * 1024;
+const DEFAULT_MAX_BYTES: u64 = 10 * 1024 * 1024;
+
+/// At most this many uploads are processed at the same time; further
+/// uploads wait for a slot. Bounds both CPU use and decoder memory
+/// (`MAX_IMAGE_ALLOC_BYTES` each).
+static PIPELINE_SLOTS: Semaphore = Semaphore::const_new(2);
+
+/// Upload rules of one collection.
+#[derive(Debug, PartialEq)]
+pub(crate) struct UploadPolicy {
+    pub(crate) max_bytes: u64,
+    pub(crate) mime_types: Vec<String>,
+    pub(crate) owner_field: String,
+}
+
+impl UploadPolicy {
+    /// Policy for `collection`, or `None` if uploads to it aren't enabled.
+    pub(crate) fn from_settings(settings: &Item, collection: &str) -> Option<Self> {
+        let enabled = settings.safe_str("security_upload_collections", "");
+        if !enabled.split(',').any(|c| c.trim() == collection) {
+            return None;
+        }
+        let key = |name: &str| format!("security_upload_{}_{}", collection, name);
+        Some(UploadPolicy {
+            max_bytes: settings.safe_u64(&key("max_bytes"), DEFAULT_MAX_BYTES),
+            mime_types: settings
+                .safe_str(&key("mime"), "image/png,image/jpeg")
+                .split(',')
+                .map(|m| m.trim().to_lowercase())
+                .filter(|m| !m.is_empty())
+                .collect(),
+            owner_field: settings.safe_str(&key("owner"), "owner"),
+        })
+    }
+
+    pub(crate) fn allows(&self, mime: &str) -> bool {
+        self.mime_types.iter().any(|m| m == mime)
+    }
+}
+
+/// Collection and field names end up in directory names and item keys.
+pub(crate) fn is_safe_name(s: &str) -> bool {
+    !s.is_empty()
+        && s.len() <= 64
+        && s.bytes()
+            .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'_')
+}
+
+/// Staging file for one upload to `name` inside `dir`. The random part keeps
+/// concurrent uploads to the same target from sharing a file.
+pub(crate) fn stage_path(dir: &Path, name: &str) -> Result<PathBuf, String> {
+    let mut nonce = [0u8; 8];
+    crate::secrets::random_bytes(&mut nonce).map_err(|e| e.to_string())?;
+    Ok(dir.join(format!("{}.{}.stage", name, crate::secrets::to_hex(&nonce))))
+}
+
+/// Move the first file of a multipart upload to `stage` and check its size.
+/// Whatever happens, nothing is left at `stage` on error.
+pub(crate) fn stage_upload(post_itm: &Item, stage: &Path, max_bytes: u64) -> Result<(), String> {
+    let files = post_itm.safe_strstr("multipart-files", &HashMap::new());
+    let src = match files.into_values().next() {
+        Some(v) => v,
+        None => return Err("no file in upload".to_string()),
+    };
+    if let Some(dir) = stage.parent() {
+        let _ = fs::create_dir_all(dir);
+    }
+    if let Err(e) = fs::rename(&src, stage) {
+        return Err(format!("failed to stage {}: {}", src, e));
+    }
+    match fs::metadata(stage) {
+        Ok(md) if md.len() <= max_bytes => Ok(()),
+        _ => {
+            let _ = fs::remove_file(stage);
+            Err(format!("upload exceeds {} bytes", max_bytes))
+        }
+    }
+}
+
+/// MIME type of the file at `path` judged by its content, for the types the
+/// pipeline knows how to handle.
+pub(crate) fn sniff_mime(path: &Path) -> Option<&'static str> {
+    let mut head = Vec::with_capacity(64);
+    fs::File::open(path)
+        .ok()?
+        .take(64)
+        .read_to_end(&mut head)
+        .ok()?;
+    if head.starts_with(b"%PDF-") {
+        return Some("application/pdf");
+    }
+    image::guess_format(&head).ok().map(|f| f.to_mime_type())
+}
+
+/// Turn a staged upload of type `mime` into the bytes to store and their
+/// MIME type. Images are re-encoded as PNG, which drops metadata and
+/// anything appended to the pixel data; other types are kept verbatim.
+/// Blocking; run it through `run_blocking`.
+pub(crate) fn sanitize(path: &Path, mime: &str) -> Result<(Vec<u8>, &'static str), String> {
+    if mime.starts_with("image/") {
+        let img = decode_image(path).map_err(|e| e.to_string())?;
+        let out = encode_png(&img.to_rgba8()).map_err(|e| e.to_string())?;
+        return Ok((out, "image/png"));
+    }
+    match mime {
+        "application/pdf" => fs::read(path)
+            .map(|d| (d, "application/pdf"))
+            .map_err(|e| e.to_string()),
+        _ => Err(format!("no handler for {}", mime)),
+    }
+}
+
+/// File extension for a MIME type `sanitize` produces.
+pub(crate) fn extension(mime: &str) -> &'static str {
+    match mime {
+        "image/png" => "png",
+        "application/pdf" => "pdf",
+        _ => "bin",
+    }
+}
+
+/// Run CPU-bound processing on the blocking pool, at most
+/// `PIPELINE_SLOTS` jobs at once, so the actor and other hooks aren't
+/// stalled.
+pub(crate) async fn run_blocking<T: Send + 'static>(
+    f: impl FnOnce() -> T + Send + 'static,
+) -> Result<T, String> {
+    let _permit = PIPELINE_SLOTS.acquire().await.map_err(|e| e.to_string())?;
+    tokio::task::spawn_blocking(f)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+pub(crate) fn encode_png(img: &image::RgbaImage) -> Result<Vec<u8>, image::ImageError> {
+    let mut out: Vec<u8> = Vec::new();
+    let encoder = image::codecs::png::PngEncoder::new(&mut out);
+    encoder.write_image(
+        img,
+        img.width(),
+        img.height(),
+        image::ColorType::Rgba8.into(),
+    )?;
+    Ok(out)
+}
+
+fn decode_limits() -> image::Limits {
+    let mut limits = image::Limits::default();
+    limits.max_image_width = Some(MAX_IMAGE_DIMENSION);
+    limits.max_image_height = Some(MAX_IMAGE_DIMENSION);
+    limits.max_alloc = Some(MAX_IMAGE_ALLOC_BYTES);
+    limits
+}
+
+/// Decode an uploaded image in any format the `image` crate knows, detected
+/// from content. Uploads are stored as stills: animated and multi-frame
+/// inputs (GIF, APNG, animated WebP, multi-page TIFF) always yield their
+/// first frame, fully composited. Keeping the animation would need an
+/// animated WebP encoder, which the `image` crate doesn't have.
+pub(crate) fn decode_image(path: &Path) -> Result<image::DynamicImage, image::ImageError> {
+    use image::codecs::{gif::GifDecoder, png::PngDecoder, webp::WebPDecoder};
+    use image::{AnimationDecoder, ImageFormat};
+    use std::io::BufReader;
+
+    let mut reader = image::ImageReader::open(path)?.with_guessed_format()?;
+    let open = || fs::File::open(path).map(BufReader::new);
+    match reader.format() {
+        Some(ImageFormat::Gif) => {
+            let mut dec = GifDecoder::new(open()?)?;
+            limit_decoder(&mut dec)?;
+            first_frame(dec.into_frames())
+        }
+        Some(ImageFormat::Png) => {
+            let mut dec = PngDecoder::with_limits(open()?, decode_limits())?;
+            limit_decoder(&mut dec)?;
+            if dec.is_apng()? {
+                // The default image of an APNG need not be part of the
+                // animation; the first animation frame is what viewers show.
+                first_frame(dec.apng()?.into_frames())
+            } else {
+                image::DynamicImage::from_decoder(dec)
+            }
+        }
+        Some(ImageFormat::WebP) => {
+            let mut dec = WebPDecoder::new(open()?)?;
+            limit_decoder(&mut dec)?;
+            if dec.has_animation() {
+                first_frame(dec.into_frames())
+            } else {
+                image::DynamicImage::from_decoder(dec)
+            }
+        }
+        // Everything else, TIFF included, decodes its first image only.
+        _ => {
+            reader.limits(decode_limits());
+            reader.decode()
+        }
+    }
+}
+
+/// Apply the decode limits to `dec`, charging its output buffer up front the
+/// way `ImageReader::decode` does; decoders only check dimensions themselves.
+fn limit_decoder(dec: &mut impl image::ImageDecoder) -> Result<(), image::ImageError> {
+    let mut limits = decode_limits();
+    limits.reserve(dec.total_bytes())?;
+    dec.set_limits(limits)
+}
+
+fn first_frame(mut frames: image::Frames) -> Result<image::DynamicImage, image::ImageError> {
+    match frames.next() {
+        Some(frame) => Ok(image::DynamicImage::ImageRgba8(frame?.into_buffer())),
+        None => Err(image::ImageError::Decoding(
+            image::error::DecodingError::from_format_hint(image::error::ImageFormatHint::Unknown),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn upload_item(file_path: &Path) -> Item {
+        let mut files = HashMap::new();
+        files.insert("file1".to_string(), file_path.to_string_lossy().to_string());
+        let mut itm = Item::new();
+        itm.set_strstr("multipart-files", &files);
+        itm
+    }
+
+    #[test]
+    fn policy_requires_opt_in() {
+        let mut settings = Item::new();
+        assert!(UploadPolicy::from_settings(&settings, "company").is_none());
+        settings.set_str("security_upload_collections", "document, company");
+        settings.set_str("security_upload_company_mime", "image/PNG,application/pdf");
+        settings.set_u64("security_upload_company_max_bytes", 1000);
+        let p = UploadPolicy::from_settings(&settings, "company").unwrap();
+        assert_eq!(p.max_bytes, 1000);
+        assert!(p.allows("image/png"));
+        assert!(p.allows("application/pdf"));
+        assert!(!p.allows("image/jpeg"));
+        assert_eq!(p.owner_field, "owner");
+        let d = UploadPolicy::from_settings(&settings, "document").unwrap();
+        assert_eq!(d.max_bytes, DEFAULT_MAX_BYTES);
+        assert!(UploadPolicy::from_settings(&settings, "compan").is_none());
+    }
+
+    #[test]
+    fn safe_names() {
+        assert!(is_safe_name("company_logo"));
+        assert!(!is_safe_name(""));
+        assert!(!is_safe_name("../user"));
+        assert!(!is_safe_name("Company"));
+        assert!(!is_safe_name(&"a".repeat(65)));
+    }
+
+    #[test]
+    fn stage_upload_checks_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("upload");
+        let stage = dir.path().join("files/1-logo.stage");
+        fs::write(&src, b"12345").unwrap();
+        assert!(stage_upload(&upload_item(&src), &stage, 4).is_err());
+        assert!(!stage.exists());
+
+        fs::write(&src, b"12345").unwrap();
+        assert!(stage_upload(&upload_item(&src), &stage, 5).is_ok());
+        assert_eq!(fs::read(&stage).unwrap(), b"12345");
+        assert!(!src.exists());
+        assert!(stage_upload(&Item::new(), &stage, 5).is_err());
+    }
+
+    #[test]
+    fn sniff_and_sanitize_by_content() {
+        let dir = tempfile::tempdir().unwrap();
+        let pdf = dir.path().join("doc.png");
+        fs::write(&pdf, b"%PDF-1.7\n...").unwrap();
+        assert_eq!(sniff_mime(&pdf), Some("application/pdf"));
+        assert_eq!(
+            sanitize(&pdf, "application/pdf").unwrap().1,
+            "application/pdf"
+        );
+
+        let jpg = dir.path().join("logo.pdf");
+        image::RgbImage::from_pixel(3, 2, image::Rgb([0, 0, 255]))
+            .save_with_format(&jpg, image::ImageFormat::Jpeg)
+            .unwrap();
+        // Trailing data after the image, as in a polyglot file.
+        let mut data = fs::read(&jpg).unwrap();
+        data.extend_from_slice(b"<script>alert(1)</script>");
+        fs::write(&jpg, &data).unwrap();
+        assert_eq!(sniff_mime(&jpg), Some("image/jpeg"));
+        let (out, mime) = sanitize(&jpg, "image/jpeg").unwrap();
+        assert_eq!(mime, "image/png");
+        let img = image::load_from_memory(&out).unwrap();
+        assert_eq!((img.width(), img.height()), (3, 2));
+        assert!(!out.windows(8).any(|w| w == b"<script>"));
+
+        let txt = dir.path().join("notes");
+        fs::write(&txt, b"hello").unwrap();
+        assert_eq!(sniff_mime(&txt), None);
+    }
+}